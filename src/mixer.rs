@@ -0,0 +1,136 @@
+//! Mixer that plays multiple sounds at the same time.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use conversions::ChannelCountConverter;
+use conversions::SampleRateConverter;
+use source::Source;
+
+use Sample;
+
+/// Builds a new mixer. It consists of an input and an output.
+///
+/// The input can be used to add new sounds with `MixerInput::add`, and every sound added this
+/// way is played simultaneously with everything else that is already playing. The output
+/// implements `Source` and produces the sum of all the sounds currently playing.
+///
+/// Since the sources added to the mixer may not all share the same sample rate or channel
+/// count, `channels` and `samples_rate` fix the format produced by the output once and for all;
+/// every source is converted to match it as soon as it is added.
+pub fn mixer<S>(channels: u16, samples_rate: u32)
+                 -> (Arc<MixerInput<S>>, MixerOutput<S>)
+    where S: Sample + Send + 'static
+{
+    let input = Arc::new(MixerInput {
+        sounds: Mutex::new(Vec::new()),
+        channels: channels,
+        samples_rate: samples_rate,
+    });
+
+    let output = MixerOutput { input: input.clone() };
+
+    (input, output)
+}
+
+/// The input of the mixer.
+pub struct MixerInput<S> {
+    sounds: Mutex<Vec<Box<Source<Item = S> + Send>>>,
+    channels: u16,
+    samples_rate: u32,
+}
+
+impl<S> MixerInput<S> where S: Sample + Send + 'static {
+    /// Adds a new source to mix with the ones already playing.
+    ///
+    /// The source is converted to the mixer's channel count and sample rate so that it can be
+    /// summed sample-by-sample with everything else.
+    #[inline]
+    pub fn add<T>(&self, source: T)
+        where T: Source<Item = S> + Send + 'static
+    {
+        let input_channels = source.get_channels();
+        let input_samples_rate = source.get_samples_rate();
+
+        let converted = SampleRateConverter::new(source, input_samples_rate, self.samples_rate,
+                                                  input_channels);
+        let converted = ChannelCountConverter::new(converted, input_channels, self.channels);
+
+        self.sounds.lock().unwrap().push(Box::new(converted) as Box<_>);
+    }
+}
+
+/// The output of the mixer. Implements `Source`.
+///
+/// With no sources added, this never runs out: `next()` just keeps returning silence, so that
+/// the mixer can sit between two active sources being swapped out without the consumer having to
+/// special-case "temporarily empty" as "finished".
+pub struct MixerOutput<S> {
+    input: Arc<MixerInput<S>>,
+}
+
+impl<S> Source for MixerOutput<S> where S: Sample + Send + 'static {
+    #[inline]
+    fn get_current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn get_channels(&self) -> u16 {
+        self.input.channels
+    }
+
+    #[inline]
+    fn get_samples_rate(&self) -> u32 {
+        self.input.samples_rate
+    }
+
+    #[inline]
+    fn get_total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl<S> Iterator for MixerOutput<S> where S: Sample + Send + 'static {
+    type Item = S;
+
+    #[inline]
+    fn next(&mut self) -> Option<S> {
+        // Locking and scanning `sounds` on every single sample is unavoidable as long as `add()`
+        // can hand us a new source from another thread at any moment; the one cost we can cut is
+        // how we drop finished sources. `Vec::remove` would shift every later element down to
+        // close the gap, which is wasted work since mixing doesn't care about source order --
+        // `swap_remove` drops one in O(1) by moving the last element into its place instead.
+        let mut sounds = self.input.sounds.lock().unwrap();
+
+        let mut accumulator = 0.0f32;
+        let mut i = 0;
+        while i < sounds.len() {
+            match sounds[i].next() {
+                Some(sample) => {
+                    accumulator += sample.to_f32();
+                    i += 1;
+                },
+                None => {
+                    sounds.swap_remove(i);
+                },
+            }
+        }
+
+        let clamped = if accumulator > 1.0 {
+            1.0
+        } else if accumulator < -1.0 {
+            -1.0
+        } else {
+            accumulator
+        };
+
+        Some(Sample::from(&clamped))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, None)
+    }
+}
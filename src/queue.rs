@@ -1,7 +1,11 @@
 //! Queue that plays sounds one after the other.
 
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
@@ -13,6 +17,14 @@ use source::Zero;
 
 use Sample;
 
+// Format used to interpret the scheduling clock shared by `append_at`/`append_at_duration` and
+// `go_next`'s due-scheduled check. Sources played by the queue can come and go with different
+// rates/channel counts, so that clock can't be expressed in terms of raw emitted samples (their
+// meaning changes every time the output switches to a differently-formatted source); instead it's
+// always expressed in samples at this fixed nominal format, regardless of what's actually playing.
+const SCHEDULE_CLOCK_CHANNELS: u16 = 2;
+const SCHEDULE_CLOCK_SAMPLES_RATE: u32 = 44100;
+
 /// Builds a new queue. It consists of an input and an output.
 ///
 /// The input can be used to add sounds to the end of the queue, while the output implements
@@ -27,9 +39,46 @@ use Sample;
 pub fn queue<S>(keep_alive_if_empty: bool)
                 -> (Arc<SourcesQueueInput<S>>, SourcesQueueOutput<S>)
     where S: Sample + Send + 'static
+{
+    queue_with_transition(keep_alive_if_empty, TransitionMode::Gapless)
+}
+
+/// Same as `queue`, but lets you pick how the output transitions between consecutive sources
+/// instead of always hard-cutting from one to the next.
+pub fn queue_with_transition<S>(keep_alive_if_empty: bool, transition: TransitionMode)
+                                 -> (Arc<SourcesQueueInput<S>>, SourcesQueueOutput<S>)
+    where S: Sample + Send + 'static
+{
+    build_queue(keep_alive_if_empty, transition, None)
+}
+
+/// Same as `queue`, but bounds the ASAP queue to at most `capacity` samples of queued audio.
+///
+/// Use `SourcesQueueInput::try_append` instead of `append` to respect the limit, and
+/// `SourcesQueueInput::space_available` to see how much room is left. This is meant for
+/// decoder/transcode pipelines that push audio as it becomes available and would otherwise
+/// have to buffer an entire stream in memory while waiting for the output to catch up.
+pub fn queue_bounded<S>(keep_alive_if_empty: bool, capacity: usize)
+                        -> (Arc<SourcesQueueInput<S>>, SourcesQueueOutput<S>)
+    where S: Sample + Send + 'static
+{
+    build_queue(keep_alive_if_empty, TransitionMode::Gapless, Some(capacity))
+}
+
+fn build_queue<S>(keep_alive_if_empty: bool, transition: TransitionMode, capacity: Option<usize>)
+                   -> (Arc<SourcesQueueInput<S>>, SourcesQueueOutput<S>)
+    where S: Sample + Send + 'static
 {
     let input = Arc::new(SourcesQueueInput {
         next_sounds: Mutex::new(Vec::new()),
+        scheduled_sounds: Mutex::new(Vec::new()),
+        samples_played: AtomicUsize::new(0),
+        current_samples_rate: AtomicUsize::new(44100),
+        current_channels: AtomicUsize::new(2),
+        current_elapsed: AtomicUsize::new(0),
+        skip_current: AtomicBool::new(false),
+        capacity: capacity,
+        queued_samples: AtomicUsize::new(0),
     });
 
     let output = SourcesQueueOutput {
@@ -37,14 +86,69 @@ pub fn queue<S>(keep_alive_if_empty: bool)
         signal_after_end: None,
         input: input.clone(),
         keep_alive_if_empty: keep_alive_if_empty,
+        sample_count: 0,
+        current_elapsed: 0,
+        current_total_len: None,
+        schedule_elapsed: Duration::new(0, 0),
+        transition: transition,
+        crossfade: None,
     };
 
     (input, output)
 }
 
+/// Describes how `SourcesQueueOutput` should transition from one queued source to the next.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TransitionMode {
+    /// Switch to the next source as soon as the current one ends, with no artificial gap. This
+    /// is the behavior of `queue()`.
+    Gapless,
+
+    /// Overlap the tail of the finishing source with the head of the next one, ramping from one
+    /// to the other over the given duration.
+    Crossfade(Duration),
+}
+
 /// The input of the queue.
 pub struct SourcesQueueInput<S> {
-    next_sounds: Mutex<Vec<(Box<Source<Item = S> + Send>, Option<Sender<()>>)>>,
+    // Each entry is paired with the number of samples it contributes to `queued_samples`, so
+    // that length can be subtracted back out again once the source is picked up for playback.
+    next_sounds: Mutex<Vec<(Box<Source<Item = S> + Send>, Option<Sender<()>>, usize)>>,
+
+    // Sources appended with `append_at`/`append_at_duration`, each paired with the offset (in
+    // `SCHEDULE_CLOCK_SAMPLES_RATE`/`SCHEDULE_CLOCK_CHANNELS` samples) at which it should start.
+    // Consulted by `go_next()` ahead of `next_sounds`, so a scheduled source always takes priority
+    // over the plain ASAP queue once due.
+    scheduled_sounds: Mutex<Vec<(u64, Box<Source<Item = S> + Send>, Option<Sender<()>>)>>,
+
+    // Total number of samples produced by the output so far, kept in sync with
+    // `SourcesQueueOutput::sample_count` on every call to `next()`. Lets a caller holding only
+    // the input query playback position from another thread.
+    samples_played: AtomicUsize,
+
+    // Format of whichever source is currently playing, updated by `go_next()`. Used together
+    // with `current_elapsed` to compute `playback_duration()`.
+    current_samples_rate: AtomicUsize,
+    current_channels: AtomicUsize,
+
+    // Samples produced since the current source started, as opposed to `samples_played` which
+    // never resets. Mirrors `SourcesQueueOutput::current_elapsed`; reset to zero (or to however
+    // much of the new source has already played, for a mid-crossfade switch) every time
+    // `current` changes.
+    current_elapsed: AtomicUsize,
+
+    // Set by `skip_current()` and checked at the top of `SourcesQueueOutput::next()`; tells the
+    // output to abandon `current` and move on, even if it still has samples left.
+    skip_current: AtomicBool,
+
+    // High-water mark for `queued_samples`, in samples. `None` means the queue is unbounded
+    // (the default, and the only option when constructed via `queue`/`queue_with_transition`).
+    capacity: Option<usize>,
+
+    // Running total of how many samples worth of audio are currently sitting in `next_sounds`.
+    // Sources whose length can't be determined (their `size_hint()` bounds don't agree) don't
+    // contribute to this count.
+    queued_samples: AtomicUsize,
 }
 
 impl<S> SourcesQueueInput<S> where S: Sample + Send + 'static {
@@ -53,7 +157,11 @@ impl<S> SourcesQueueInput<S> where S: Sample + Send + 'static {
     pub fn append<T>(&self, source: T)
         where T: Source<Item = S> + Send + 'static
     {
-        self.next_sounds.lock().unwrap().push((Box::new(source) as Box<_>, None));
+        // An unbounded queue never consults `queued_samples` for backpressure, so a source whose
+        // length isn't knowable up front can just count as zero here.
+        let len = source_len_samples(&source).unwrap_or(0);
+        self.queued_samples.fetch_add(len, Ordering::Relaxed);
+        self.next_sounds.lock().unwrap().push((Box::new(source) as Box<_>, None, len));
     }
 
     /// Adds a new source to the end of the queue.
@@ -64,9 +172,139 @@ impl<S> SourcesQueueInput<S> where S: Sample + Send + 'static {
         where T: Source<Item = S> + Send + 'static
     {
         let (tx, rx) = mpsc::channel();
-        self.next_sounds.lock().unwrap().push((Box::new(source) as Box<_>, Some(tx)));
+        let len = source_len_samples(&source).unwrap_or(0);
+        self.queued_samples.fetch_add(len, Ordering::Relaxed);
+        self.next_sounds.lock().unwrap().push((Box::new(source) as Box<_>, Some(tx), len));
         rx
     }
+
+    /// Like `append`, but if this queue was built with `queue_bounded`, the source is rejected
+    /// (returned back to the caller) instead of being queued when either appending it would push
+    /// the total queued audio past the configured capacity, or its length can't be determined up
+    /// front (since then there would be nothing to bound in the first place).
+    ///
+    /// Always succeeds on an unbounded queue (one built with `queue`/`queue_with_transition`).
+    #[inline]
+    pub fn try_append<T>(&self, source: T) -> Result<(), T>
+        where T: Source<Item = S> + Send + 'static
+    {
+        let len = match source_len_samples(&source) {
+            Some(len) => len,
+            None => {
+                if self.capacity.is_some() {
+                    return Err(source);
+                }
+                0
+            },
+        };
+
+        if let Some(capacity) = self.capacity {
+            // Reserve the space with a compare-and-swap loop rather than a plain load followed by
+            // `fetch_add`, since two callers could otherwise both pass the capacity check on the
+            // same stale reading and together push `queued_samples` past `capacity`.
+            loop {
+                let current = self.queued_samples.load(Ordering::Relaxed);
+                if current.saturating_add(len) > capacity {
+                    return Err(source);
+                }
+
+                let result = self.queued_samples.compare_exchange(current, current + len,
+                                                                    Ordering::Relaxed,
+                                                                    Ordering::Relaxed);
+                if result.is_ok() {
+                    break;
+                }
+            }
+        } else {
+            self.queued_samples.fetch_add(len, Ordering::Relaxed);
+        }
+
+        self.next_sounds.lock().unwrap().push((Box::new(source) as Box<_>, None, len));
+        Ok(())
+    }
+
+    /// Returns how many more samples can be pushed onto the queue before reaching the capacity
+    /// configured with `queue_bounded`, or `None` if this queue is unbounded.
+    #[inline]
+    pub fn space_available(&self) -> Option<usize> {
+        self.capacity.map(|capacity| capacity.saturating_sub(self.queued_samples.load(Ordering::Relaxed)))
+    }
+
+    /// Schedules a new source to start playing once the queue's scheduling clock has advanced
+    /// `start_samples` samples since the queue was created, counted at a fixed nominal rate of
+    /// `SCHEDULE_CLOCK_SAMPLES_RATE` Hz / `SCHEDULE_CLOCK_CHANNELS` channels (not the rate of
+    /// whatever happens to be playing, which can change from source to source). Prefer
+    /// `append_at_duration` unless you specifically need to count in that fixed format.
+    ///
+    /// If by the time the queue would reach this source `start_samples` has already passed,
+    /// it starts immediately instead of being delayed further.
+    #[inline]
+    pub fn append_at<T>(&self, start_samples: u64, source: T)
+        where T: Source<Item = S> + Send + 'static
+    {
+        self.scheduled_sounds.lock().unwrap().push((start_samples, Box::new(source) as Box<_>, None));
+    }
+
+    /// Same as `append_at`, but the start time is expressed as a `Duration` from the start of
+    /// playback rather than as a sample count.
+    #[inline]
+    pub fn append_at_duration<T>(&self, start: Duration, source: T)
+        where T: Source<Item = S> + Send + 'static
+    {
+        let start_samples = duration_to_samples(start, SCHEDULE_CLOCK_CHANNELS,
+                                                  SCHEDULE_CLOCK_SAMPLES_RATE) as u64;
+        self.append_at(start_samples, source);
+    }
+
+    /// Returns the total number of samples produced by the output so far.
+    #[inline]
+    pub fn samples_written(&self) -> u64 {
+        self.samples_played.load(Ordering::Relaxed) as u64
+    }
+
+    /// Returns the elapsed playback time of whichever source is currently playing, computed from
+    /// the number of samples produced since it started and its format.
+    ///
+    /// This is reset every time the output moves on to a new source; see `samples_written()` for
+    /// a count that only ever grows.
+    #[inline]
+    pub fn playback_duration(&self) -> Duration {
+        let rate = self.current_samples_rate.load(Ordering::Relaxed) as u32;
+        let channels = self.current_channels.load(Ordering::Relaxed) as u16;
+        let elapsed = self.current_elapsed.load(Ordering::Relaxed) as u64;
+        samples_to_duration(elapsed, channels, rate)
+    }
+
+    /// Returns the number of sources currently waiting in the ASAP queue (not counting
+    /// scheduled sources added with `append_at`).
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.next_sounds.lock().unwrap().len()
+    }
+
+    /// Returns `true` if there is nothing waiting in the ASAP queue.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every source currently waiting in the ASAP queue, without affecting whatever is
+    /// playing right now or sources scheduled with `append_at`.
+    #[inline]
+    pub fn clear(&self) {
+        let mut next_sounds = self.next_sounds.lock().unwrap();
+        let cleared_len: usize = next_sounds.iter().map(|&(_, _, len)| len).sum();
+        self.queued_samples.fetch_sub(cleared_len, Ordering::Relaxed);
+        next_sounds.clear();
+    }
+
+    /// Forces the output to stop playing its current source and move on to the next one, as if
+    /// it had finished naturally. The source's `append_with_signal` receiver, if any, is still
+    /// signalled.
+    #[inline]
+    pub fn skip_current(&self) {
+        self.skip_current.store(true, Ordering::Relaxed);
+    }
 }
 
 /// The output of the queue. Implements `Source`.
@@ -82,6 +320,44 @@ pub struct SourcesQueueOutput<S> {
 
     // See constructor.
     keep_alive_if_empty: bool,
+
+    // Total number of samples produced so far, kept in step with
+    // `SourcesQueueInput::samples_played`. Not used for scheduling; see `schedule_elapsed`.
+    sample_count: u64,
+
+    // Samples produced since `current` started playing. Reset on every source switch; mirrored
+    // into `SourcesQueueInput::current_elapsed` for `playback_duration()`.
+    current_elapsed: u64,
+
+    // Total length of `current`, in samples, if it reports one via `get_total_duration()`.
+    // `current_total_len - current_elapsed` is the only reliable "samples remaining" signal we
+    // have (`get_current_frame_len()` is distance to the next frame boundary, not to the end of
+    // the source) -- `try_begin_crossfade` needs this to know when to start fading.
+    current_total_len: Option<u64>,
+
+    // Wall-clock time elapsed since the queue was created, accumulated a source at a time as
+    // `go_next()` moves past each one (using that source's own rate/channels to convert its
+    // `current_elapsed` into a `Duration`). This -- rather than `sample_count`, which counts raw
+    // samples in whatever format is currently playing -- is what `scheduled_sounds` offsets are
+    // compared against, since it stays in one fixed unit across source switches.
+    schedule_elapsed: Duration,
+
+    // See constructor.
+    transition: TransitionMode,
+
+    // Set while a `TransitionMode::Crossfade` is in progress; `None` the rest of the time.
+    crossfade: Option<Crossfade<S>>,
+}
+
+// State for an in-progress crossfade: the buffered tail of the outgoing source, and the
+// incoming source being pulled from and blended in sample-by-sample.
+struct Crossfade<S> {
+    outgoing: VecDeque<S>,
+    incoming: Box<Source<Item = S> + Send>,
+    incoming_signal: Option<Sender<()>>,
+    incoming_total_len: Option<u64>,
+    len: usize,
+    pos: usize,
 }
 
 impl<S> Source for SourcesQueueOutput<S> where S: Sample + Send + 'static {
@@ -112,8 +388,67 @@ impl<S> Iterator for SourcesQueueOutput<S> where S: Sample + Send + 'static {
     #[inline]
     fn next(&mut self) -> Option<S> {
         loop {
+            // A call to `SourcesQueueInput::skip_current()` forces us to abandon `current` right
+            // away, regardless of whether it still has samples left.
+            if self.input.skip_current.swap(false, Ordering::Relaxed) {
+                if let Some(crossfade) = self.crossfade.take() {
+                    // The incoming source was already pulled out of `next_sounds` to start the
+                    // crossfade, so it needs its own `append_with_signal` waiter (if any) fired
+                    // here -- `go_next()` only signals `self.signal_after_end`, which
+                    // `try_begin_crossfade` already fired and cleared for the outgoing source.
+                    if let Some(incoming_signal) = crossfade.incoming_signal {
+                        let _ = incoming_signal.send(());
+                    }
+                }
+                if self.go_next().is_err() {
+                    return None;
+                }
+                continue;
+            }
+
+            // If we're due to start crossfading into the next source, kick it off now, before
+            // `current` actually runs out.
+            if self.crossfade.is_none() {
+                if let TransitionMode::Crossfade(duration) = self.transition {
+                    self.try_begin_crossfade(duration);
+                }
+            }
+
+            if let Some(mut crossfade) = self.crossfade.take() {
+                let sample = self.mix_crossfade_sample(&mut crossfade);
+
+                if crossfade.pos < crossfade.len {
+                    // `current_elapsed` already reached the outgoing source's `total_len` when
+                    // `try_begin_crossfade` buffered its tail; don't advance it further while
+                    // blending that buffered tail, or it (and the `schedule_elapsed` it later
+                    // folds into) would overshoot by `crossfade_len` on every crossfaded switch.
+                    self.crossfade = Some(crossfade);
+                } else {
+                    if self.current_elapsed > 0 {
+                        self.schedule_elapsed += samples_to_duration(self.current_elapsed,
+                            self.current.get_channels(), self.current.get_samples_rate());
+                    }
+
+                    // `crossfade.incoming` has already had `crossfade.len` samples pulled from it
+                    // to produce the blended output, so that's its starting elapsed count, not 0.
+                    self.current_elapsed = crossfade.len as u64;
+                    self.current_total_len = crossfade.incoming_total_len;
+                    self.current = crossfade.incoming;
+                    self.signal_after_end = crossfade.incoming_signal;
+                }
+
+                self.sample_count += 1;
+                self.input.samples_played.fetch_add(1, Ordering::Relaxed);
+                self.input.current_elapsed.store(self.current_elapsed as usize, Ordering::Relaxed);
+                return Some(sample);
+            }
+
             // Basic situation that will happen most of the time.
             if let Some(sample) = self.current.next() {
+                self.sample_count += 1;
+                self.current_elapsed += 1;
+                self.input.samples_played.fetch_add(1, Ordering::Relaxed);
+                self.input.current_elapsed.store(self.current_elapsed as usize, Ordering::Relaxed);
                 return Some(sample);
             }
 
@@ -141,7 +476,74 @@ impl<S> SourcesQueueOutput<S> where S: Sample + Send + 'static {
             let _ = signal_after_end.send(());
         }
 
-        let (next, signal_after_end) = {
+        // Fold the source we're about to leave into the scheduling clock before comparing it
+        // against anything in `scheduled_sounds`.
+        if self.current_elapsed > 0 {
+            self.schedule_elapsed += samples_to_duration(self.current_elapsed,
+                self.current.get_channels(), self.current.get_samples_rate());
+        }
+        let schedule_clock = duration_to_samples(self.schedule_elapsed, SCHEDULE_CLOCK_CHANNELS,
+                                                  SCHEDULE_CLOCK_SAMPLES_RATE) as u64;
+
+        // Scheduled sources always take priority over the plain ASAP queue: if one of them is
+        // due, play it now. If the earliest one is still in the future, only play silence up to
+        // its start time if there's nothing else to play in the meantime -- otherwise plain
+        // `append`ed sources would be starved for as long as anything remained scheduled.
+        let due_scheduled = {
+            let mut scheduled = self.input.scheduled_sounds.lock().unwrap();
+            // Several entries can be due at once; always take the earliest-starting one rather
+            // than whichever happens to have been inserted first, so out-of-order `append_at`
+            // calls still play back in schedule order.
+            scheduled.iter().enumerate()
+                .filter(|&(_, entry)| entry.0 <= schedule_clock)
+                .min_by_key(|&(_, entry)| entry.0)
+                .map(|(index, _)| index)
+                .map(|index| scheduled.remove(index))
+        };
+
+        if let Some((_, source, signal_after_end)) = due_scheduled {
+            self.input.current_samples_rate.store(source.get_samples_rate() as usize, Ordering::Relaxed);
+            self.input.current_channels.store(source.get_channels() as usize, Ordering::Relaxed);
+            self.current_elapsed = 0;
+            self.input.current_elapsed.store(0, Ordering::Relaxed);
+            self.current_total_len = total_len_samples(&*source);
+            self.current = source;
+            self.signal_after_end = signal_after_end;
+            return Ok(());
+        }
+
+        let next_start = {
+            let scheduled = self.input.scheduled_sounds.lock().unwrap();
+            scheduled.iter().map(|&(start, _, _)| start).min()
+        };
+
+        if let Some(start_samples) = next_start {
+            let asap_empty = self.input.next_sounds.lock().unwrap().is_empty();
+
+            if asap_empty {
+                // `silence_len` is counted in the scheduling clock's own units, so it must be
+                // converted to a `Duration` the same way -- not with the unrelated 1ch/44000Hz
+                // format the silence happens to be synthesized in below.
+                let silence_len = start_samples - schedule_clock;
+                let silence_duration = samples_to_duration(silence_len, SCHEDULE_CLOCK_CHANNELS,
+                                                             SCHEDULE_CLOCK_SAMPLES_RATE);
+                let silence = Zero::<S>::new(1, 44000);
+                let silence = silence.take_duration(silence_duration);
+                self.input.current_samples_rate.store(44000, Ordering::Relaxed);
+                self.input.current_channels.store(1, Ordering::Relaxed);
+                self.current_elapsed = 0;
+                self.input.current_elapsed.store(0, Ordering::Relaxed);
+                self.current_total_len = total_len_samples(&silence);
+                self.current = Box::new(silence) as Box<_>;
+                self.signal_after_end = None;
+                return Ok(());
+            }
+
+            // Otherwise the scheduled source stays in `scheduled_sounds` for the next `go_next()`
+            // call to reconsider, and we fall through to play whatever's waiting in `next_sounds`.
+        }
+
+        let (next, signal_after_end): (Box<Source<Item = S> + Send>, _) = {
             let mut next = self.input.next_sounds.lock().unwrap();
 
             if next.len() == 0 {
@@ -153,12 +555,288 @@ impl<S> SourcesQueueOutput<S> where S: Sample + Send + 'static {
                     return Err(());
                 }
             } else {
-                next.remove(0)
+                let (source, signal_after_end, len) = next.remove(0);
+                self.input.queued_samples.fetch_sub(len, Ordering::Relaxed);
+                (source, signal_after_end)
             }
         };
 
+        self.input.current_samples_rate.store(next.get_samples_rate() as usize, Ordering::Relaxed);
+        self.input.current_channels.store(next.get_channels() as usize, Ordering::Relaxed);
+        self.current_elapsed = 0;
+        self.input.current_elapsed.store(0, Ordering::Relaxed);
+        self.current_total_len = total_len_samples(&*next);
         self.current = next;
         self.signal_after_end = signal_after_end;
         Ok(())
     }
+
+    // If `current` is close enough to its end and the ASAP queue has something waiting, starts
+    // crossfading into it: buffers the actual tail of `current`, pops the next source, and sets
+    // `self.crossfade` so that subsequent calls to `next()` blend the two together.
+    fn try_begin_crossfade(&mut self, duration: Duration) {
+        // `current_total_len - current_elapsed` is the only samples-remaining signal we can
+        // trust; `get_current_frame_len()` is the distance to the next frame boundary, not to
+        // the end of the source, so sources that don't report a total duration just hard-cut.
+        let total_len = match self.current_total_len {
+            Some(total_len) => total_len,
+            None => return,
+        };
+
+        let crossfade_len = duration_to_samples(duration, self.current.get_channels(),
+                                                 self.current.get_samples_rate()) as u64;
+        let remaining = total_len.saturating_sub(self.current_elapsed);
+        if remaining == 0 || remaining > crossfade_len {
+            return;
+        }
+
+        let (next, signal_after_end) = {
+            let mut next_sounds = self.input.next_sounds.lock().unwrap();
+            if next_sounds.is_empty() {
+                return;
+            }
+            let (source, signal_after_end, len) = next_sounds.remove(0);
+            self.input.queued_samples.fetch_sub(len, Ordering::Relaxed);
+            (source, signal_after_end)
+        };
+
+        if let Some(signal_after_end) = self.signal_after_end.take() {
+            let _ = signal_after_end.send(());
+        }
+
+        // Buffer only the actual tail of the outgoing source (bounded by `remaining`, which is
+        // in turn bounded by `crossfade_len`), not the whole rest of the track. The fade itself
+        // still ramps over the full `crossfade_len` samples below -- once the buffered tail runs
+        // out, `mix_crossfade_sample` blends the incoming source against silence instead.
+        let mut outgoing = VecDeque::with_capacity(remaining as usize);
+        for _ in 0..remaining {
+            match self.current.next() {
+                Some(sample) => outgoing.push_back(sample),
+                None => break,
+            }
+        }
+        self.current_elapsed += outgoing.len() as u64;
+
+        let incoming_total_len = total_len_samples(&*next);
+        self.input.current_samples_rate.store(next.get_samples_rate() as usize, Ordering::Relaxed);
+        self.input.current_channels.store(next.get_channels() as usize, Ordering::Relaxed);
+
+        self.crossfade = Some(Crossfade {
+            outgoing: outgoing,
+            incoming: next,
+            incoming_signal: signal_after_end,
+            incoming_total_len: incoming_total_len,
+            len: crossfade_len as usize,
+            pos: 0,
+        });
+    }
+
+    // Produces the next blended sample of an in-progress crossfade and advances its position.
+    fn mix_crossfade_sample(&mut self, crossfade: &mut Crossfade<S>) -> S {
+        let outgoing = crossfade.outgoing.pop_front().unwrap_or(Sample::from(&0.0f32));
+        let incoming = crossfade.incoming.next().unwrap_or(Sample::from(&0.0f32));
+
+        let t = crossfade.pos as f32 / crossfade.len as f32;
+        let mixed = outgoing.to_f32() * (1.0 - t) + incoming.to_f32() * t;
+
+        crossfade.pos += 1;
+        Sample::from(&mixed)
+    }
+}
+
+// Returns exactly how many samples a source will contribute to the queue, if that's knowable up
+// front -- i.e. its size hint's lower and upper bounds agree, as for anything backed by an
+// in-memory buffer (which is what callers of `queue_bounded` push). A bare lower bound isn't good
+// enough here: plenty of sources (including open-ended ones) report a lower bound of 0 while
+// still being unbounded in length, and treating that the same as "genuinely empty" would let
+// `try_append` admit them for free instead of bounding them. `None` means the length genuinely
+// isn't known ahead of time.
+fn source_len_samples<S, T>(source: &T) -> Option<usize>
+    where S: Sample, T: Source<Item = S>
+{
+    match source.size_hint() {
+        (lower, Some(upper)) if lower == upper => Some(lower),
+        _ => None,
+    }
+}
+
+// Returns the total length of a source in samples, computed from `get_total_duration()` rather
+// than from the size hint as `source_len_samples` does, or `None` when the source doesn't report
+// a total duration. Used where an actual "samples remaining" signal is needed.
+fn total_len_samples<S>(source: &(Source<Item = S> + Send)) -> Option<u64>
+    where S: Sample
+{
+    source.get_total_duration()
+        .map(|duration| duration_to_samples(duration, source.get_channels(), source.get_samples_rate()) as u64)
+}
+
+// Converts a `Duration` into the number of samples (at the given channel count and sample rate)
+// it corresponds to. Inverse of `samples_to_duration`.
+fn duration_to_samples(duration: Duration, channels: u16, samples_rate: u32) -> usize {
+    let samples_per_sec = channels as u64 * samples_rate as u64;
+    let nanos = duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64;
+    (nanos * samples_per_sec / 1_000_000_000) as usize
+}
+
+// Converts a number of samples (at the given channel count and sample rate) into the closest
+// `Duration`, for feeding into `Source::take_duration`.
+fn samples_to_duration(samples: u64, channels: u16, samples_rate: u32) -> Duration {
+    let samples_per_sec = channels as u64 * samples_rate as u64;
+    let secs = samples / samples_per_sec;
+    let remainder = samples % samples_per_sec;
+    let nanos = remainder * 1_000_000_000 / samples_per_sec;
+    Duration::new(secs, nanos as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buffer::SamplesBuffer;
+
+    // A source whose length genuinely can't be known ahead of time (its `size_hint` keeps the
+    // `Iterator` default of `(0, None)`), standing in for a live/streaming feed.
+    struct OpenEnded;
+
+    impl Iterator for OpenEnded {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            Some(0.0)
+        }
+    }
+
+    impl Source for OpenEnded {
+        fn get_current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn get_channels(&self) -> u16 {
+            1
+        }
+
+        fn get_samples_rate(&self) -> u32 {
+            1
+        }
+
+        fn get_total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    #[test]
+    fn plays_sources_in_order() {
+        let (queue_in, mut queue_out) = queue::<f32>(false);
+        queue_in.append(SamplesBuffer::new(1, 1, vec![10.0, -10.0, 10.0, -10.0]));
+        queue_in.append(SamplesBuffer::new(1, 1, vec![5.0, 5.0]));
+
+        assert_eq!(queue_out.next(), Some(10.0));
+        assert_eq!(queue_out.next(), Some(-10.0));
+        assert_eq!(queue_out.next(), Some(10.0));
+        assert_eq!(queue_out.next(), Some(-10.0));
+        assert_eq!(queue_out.next(), Some(5.0));
+        assert_eq!(queue_out.next(), Some(5.0));
+        assert_eq!(queue_out.next(), None);
+    }
+
+    #[test]
+    fn skip_current_signals_and_advances_immediately() {
+        let (queue_in, mut queue_out) = queue::<f32>(false);
+        let rx = queue_in.append_with_signal(SamplesBuffer::new(1, 1, vec![1.0, 1.0, 1.0, 1.0]));
+        queue_in.append(SamplesBuffer::new(1, 1, vec![2.0]));
+
+        assert_eq!(queue_out.next(), Some(1.0));
+        assert!(rx.try_recv().is_err());
+
+        queue_in.skip_current();
+        assert_eq!(queue_out.next(), Some(2.0));
+        rx.try_recv().expect("skip_current should still signal the abandoned source");
+    }
+
+    #[test]
+    fn clear_drops_only_the_asap_queue() {
+        let (queue_in, mut queue_out) = queue::<f32>(false);
+        queue_in.append(SamplesBuffer::new(1, 1, vec![1.0, 1.0]));
+        queue_in.append(SamplesBuffer::new(1, 1, vec![2.0, 2.0]));
+
+        assert_eq!(queue_out.next(), Some(1.0));
+        assert_eq!(queue_in.len(), 1);
+
+        queue_in.clear();
+        assert_eq!(queue_in.len(), 0);
+
+        // The source already playing is unaffected by `clear()`...
+        assert_eq!(queue_out.next(), Some(1.0));
+        // ...but nothing was left behind it.
+        assert_eq!(queue_out.next(), None);
+    }
+
+    #[test]
+    fn append_at_duration_uses_the_canonical_clock_not_the_sources_format() {
+        let (queue_in, mut queue_out) = queue::<f32>(false);
+        // Advances the schedule clock by exactly 1 second (one sample at 1ch/1Hz).
+        queue_in.append(SamplesBuffer::new(1, 1, vec![9.0]));
+        // Scheduled for 1 second in, but expressed in a completely different format (2ch/48000Hz)
+        // than either the queue's canonical clock or the source above -- if this were (as it used
+        // to be) converted using the *incoming* source's own rate/channels instead of the
+        // canonical clock, it would land at the wrong offset relative to the ASAP source's 1s.
+        queue_in.append_at_duration(Duration::from_secs(1), SamplesBuffer::new(2, 48000, vec![2.0, 2.0]));
+
+        assert_eq!(queue_out.next(), Some(9.0));
+        assert_eq!(queue_out.next(), Some(2.0));
+        assert_eq!(queue_out.next(), Some(2.0));
+        assert_eq!(queue_out.next(), None);
+    }
+
+    #[test]
+    fn out_of_order_schedules_play_back_in_schedule_order() {
+        let (queue_in, mut queue_out) = queue::<f32>(false);
+        // One second (88200 canonical-clock samples) elapses while this plays, which is well
+        // past both entries below -- so both are due at once by the time `go_next()` next looks.
+        queue_in.append(SamplesBuffer::new(1, 1, vec![9.0]));
+        queue_in.append_at(100, SamplesBuffer::new(1, 1, vec![100.0]));
+        queue_in.append_at(50, SamplesBuffer::new(1, 1, vec![50.0]));
+
+        // Both due entries must be considered, and the earlier-starting one (appended second)
+        // must come out first, not whichever happened to be inserted first.
+        assert_eq!(queue_out.next(), Some(9.0));
+        assert_eq!(queue_out.next(), Some(50.0));
+        assert_eq!(queue_out.next(), Some(100.0));
+        assert_eq!(queue_out.next(), None);
+    }
+
+    #[test]
+    fn crossfade_ramps_over_exactly_the_requested_duration() {
+        let transition = TransitionMode::Crossfade(Duration::from_secs(2));
+        let (queue_in, mut queue_out) = queue_with_transition::<f32>(false, transition);
+        queue_in.append(SamplesBuffer::new(1, 1, vec![10.0, 20.0]));
+        queue_in.append(SamplesBuffer::new(1, 1, vec![100.0, 200.0]));
+
+        // t=0: all outgoing. t=0.5: evenly blended.
+        assert_eq!(queue_out.next(), Some(10.0));
+        assert_eq!(queue_out.next(), Some(110.0));
+        // Both 2-sample sources are exhausted by the 2-sample fade; nothing left to play.
+        assert_eq!(queue_out.next(), None);
+    }
+
+    #[test]
+    fn try_append_rejects_once_capacity_is_full() {
+        let (queue_in, _queue_out) = queue_bounded::<f32>(false, 4);
+        assert_eq!(queue_in.space_available(), Some(4));
+
+        queue_in.try_append(SamplesBuffer::new(1, 1, vec![0.0; 3])).unwrap();
+        assert_eq!(queue_in.space_available(), Some(1));
+
+        let rejected = queue_in.try_append(SamplesBuffer::new(1, 1, vec![0.0; 2]));
+        assert!(rejected.is_err());
+        assert_eq!(queue_in.space_available(), Some(1));
+    }
+
+    #[test]
+    fn try_append_rejects_sources_of_unknown_length() {
+        let (queue_in, _queue_out) = queue_bounded::<f32>(false, 1000);
+
+        let rejected = queue_in.try_append(OpenEnded);
+        assert!(rejected.is_err());
+        assert_eq!(queue_in.space_available(), Some(1000));
+    }
 }